@@ -1,4 +1,7 @@
 use elf::{endian::AnyEndian, ElfBytes};
+use num_bigint::BigUint;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::env;
 use std::fs::{self, File};
 use std::io::BufReader;
@@ -7,6 +10,7 @@ use std::time::Duration;
 
 use plonky2::field::goldilocks_field::GoldilocksField;
 use plonky2::plonk::config::{GenericConfig, PoseidonGoldilocksConfig};
+use plonky2::util::log2_ceil;
 use plonky2::util::timing::TimingTree;
 use plonky2x::backend::circuit::Groth16WrapperParameters;
 use plonky2x::backend::wrapper::wrap::WrappedCircuit;
@@ -16,6 +20,7 @@ use zkm_prover::all_stark::AllStark;
 use zkm_prover::config::StarkConfig;
 use zkm_prover::cpu::kernel::assembler::segment_kernel;
 use zkm_prover::fixed_recursive_verifier::AllRecursiveCircuits;
+use zkm_prover::generation::generate_traces;
 use zkm_prover::mips_emulator::state::{InstrumentedState, State, SEGMENT_STEPS};
 use zkm_prover::mips_emulator::utils::get_block_path;
 use zkm_prover::proof;
@@ -25,6 +30,339 @@ use zkm_prover::verifier::verify_proof;
 
 const DEGREE_BITS_RANGE: [Range<usize>; 6] = [10..21, 12..22, 12..21, 8..21, 6..21, 13..23];
 
+// Transparent compression + corruption detection for the segment files the split loop
+// writes and `aggregate_proof_all`/`prove` re-read. Framed as `SEG_COMPRESSION` rather
+// than a hard switch so segments written before this existed still load unmodified.
+mod segment_codec {
+    use std::fs::File;
+    use std::io::{self, Cursor, Read, Write};
+
+    const MAGIC: [u8; 4] = *b"ZSEG";
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum CompressionType {
+        None,
+        Lz4,
+        Miniz(u8),
+    }
+
+    impl CompressionType {
+        pub fn from_env() -> Self {
+            match std::env::var("SEG_COMPRESSION").ok().as_deref() {
+                Some("lz4") => CompressionType::Lz4,
+                Some(s) if s.starts_with("miniz") => {
+                    let level = s
+                        .strip_prefix("miniz:")
+                        .and_then(|l| l.parse().ok())
+                        .unwrap_or(6);
+                    CompressionType::Miniz(level)
+                }
+                _ => CompressionType::None,
+            }
+        }
+
+        fn id(self) -> u8 {
+            match self {
+                CompressionType::None => 0,
+                CompressionType::Lz4 => 1,
+                CompressionType::Miniz(_) => 2,
+            }
+        }
+    }
+
+    fn compress(codec: CompressionType, data: &[u8]) -> Vec<u8> {
+        match codec {
+            CompressionType::None => data.to_vec(),
+            CompressionType::Lz4 => lz4_flex::compress_prepend_size(data),
+            CompressionType::Miniz(level) => miniz_oxide::deflate::compress_to_vec(data, level),
+        }
+    }
+
+    fn decompress(codec_id: u8, data: &[u8]) -> io::Result<Vec<u8>> {
+        match codec_id {
+            0 => Ok(data.to_vec()),
+            1 => lz4_flex::decompress_size_prepended(data)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            2 => miniz_oxide::inflate::decompress_to_vec(data)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{e:?}"))),
+            other => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown segment codec id {other}"),
+            )),
+        }
+    }
+
+    fn write_framed<W: Write>(mut w: W, codec: CompressionType, data: &[u8]) -> io::Result<()> {
+        let checksum = xxhash_rust::xxh3::xxh3_64(data);
+        let payload = compress(codec, data);
+        w.write_all(&MAGIC)?;
+        w.write_all(&[codec.id()])?;
+        w.write_all(&(data.len() as u64).to_le_bytes())?;
+        w.write_all(&checksum.to_le_bytes())?;
+        w.write_all(&(payload.len() as u64).to_le_bytes())?;
+        w.write_all(&payload)
+    }
+
+    fn read_framed(raw: Vec<u8>) -> io::Result<Vec<u8>> {
+        if raw.len() < MAGIC.len() || raw[..MAGIC.len()] != MAGIC {
+            // Pre-compression segment file: load as-is so existing output keeps working.
+            return Ok(raw);
+        }
+        let mut cursor = Cursor::new(&raw[MAGIC.len()..]);
+        let mut codec_id = [0u8; 1];
+        cursor.read_exact(&mut codec_id)?;
+        let mut u64_buf = [0u8; 8];
+        cursor.read_exact(&mut u64_buf)?;
+        let uncompressed_len = u64::from_le_bytes(u64_buf) as usize;
+        cursor.read_exact(&mut u64_buf)?;
+        let checksum = u64::from_le_bytes(u64_buf);
+        cursor.read_exact(&mut u64_buf)?;
+        let payload_len = u64::from_le_bytes(u64_buf) as usize;
+        let mut payload = vec![0u8; payload_len];
+        cursor.read_exact(&mut payload)?;
+
+        let data = decompress(codec_id[0], &payload)?;
+        if data.len() != uncompressed_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "segment file length mismatch after decompression",
+            ));
+        }
+        if xxhash_rust::xxh3::xxh3_64(&data) != checksum {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "segment file failed xxh3 checksum (corrupt?)",
+            ));
+        }
+        Ok(data)
+    }
+
+    /// Buffers everything written to it and flushes it compressed + checksummed to the
+    /// underlying file once the segment writer is dropped. `InstrumentedState::split_segment`
+    /// owns the writer for the lifetime of a segment and never hands it back, so there's no
+    /// call site that could invoke an explicit close - `Drop` reports a failure via `log`
+    /// instead of discarding it, which is the best this shape of API allows.
+    pub struct CompressingWriter {
+        file: File,
+        codec: CompressionType,
+        buf: Vec<u8>,
+    }
+
+    impl CompressingWriter {
+        pub fn new(file: File, codec: CompressionType) -> Self {
+            CompressingWriter {
+                file,
+                codec,
+                buf: Vec::new(),
+            }
+        }
+    }
+
+    impl Write for CompressingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.buf.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Drop for CompressingWriter {
+        fn drop(&mut self) {
+            if let Err(e) = write_framed(&mut self.file, self.codec, &self.buf) {
+                log::error!("segment writer failed to flush on drop: {e}");
+            }
+        }
+    }
+
+    /// Reads a segment file written by `CompressingWriter` (or a legacy uncompressed one)
+    /// fully into memory and hands back a `Read` over the decoded bytes.
+    pub fn open_segment_reader(path: &str) -> io::Result<Cursor<Vec<u8>>> {
+        let raw = std::fs::read(path)?;
+        Ok(Cursor::new(read_framed(raw)?))
+    }
+}
+
+// Dispatches segment root proving and proof aggregation to either the in-process
+// pipeline (the default, so single-machine behavior is unchanged) or a cluster of
+// worker processes named by `SEG_WORKERS`. The wire/storage boundary is always a
+// serialized `Envelope<Proof>` blob, so neither the trait nor the remote client needs
+// to know the concrete plonky2 proof type `aggregate_proof_all` is instantiated with.
+mod proving_client {
+    use super::{Deserialize, Serialize};
+    use std::io::Read;
+    use std::time::Duration;
+    use zkm_prover::proof::PublicValues;
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Envelope<Proof> {
+        pub proof: Proof,
+        pub public_values: PublicValues<plonky2::field::goldilocks_field::GoldilocksField>,
+        pub is_agg: bool,
+    }
+
+    /// Blocks until the segment root proof (or aggregation) comes back, retrying on
+    /// transient worker failure.
+    pub trait SyncProver: Send + Sync {
+        fn prove_root(&self, seg_path: &str) -> anyhow::Result<Vec<u8>>;
+        fn prove_aggregation(&self, left: &[u8], right: &[u8]) -> anyhow::Result<Vec<u8>>;
+    }
+
+    /// Runs proving in-process via the closures `aggregate_proof_all` supplies. This is
+    /// the default `SyncProver`: no `SEG_WORKERS` means no network involved at all.
+    pub struct LocalSyncProver<Proof, RootFn, AggFn> {
+        root_fn: RootFn,
+        agg_fn: AggFn,
+        _proof: std::marker::PhantomData<Proof>,
+    }
+
+    impl<Proof, RootFn, AggFn> LocalSyncProver<Proof, RootFn, AggFn>
+    where
+        Proof: Serialize + for<'de> Deserialize<'de>,
+        RootFn: Fn(&str) -> anyhow::Result<(Proof, PublicValues<plonky2::field::goldilocks_field::GoldilocksField>)>
+            + Send
+            + Sync,
+        AggFn: Fn(
+                bool,
+                &Proof,
+                bool,
+                &Proof,
+                PublicValues<plonky2::field::goldilocks_field::GoldilocksField>,
+            ) -> anyhow::Result<(Proof, PublicValues<plonky2::field::goldilocks_field::GoldilocksField>)>
+            + Send
+            + Sync,
+    {
+        pub fn new(root_fn: RootFn, agg_fn: AggFn) -> Self {
+            LocalSyncProver {
+                root_fn,
+                agg_fn,
+                _proof: std::marker::PhantomData,
+            }
+        }
+    }
+
+    impl<Proof, RootFn, AggFn> SyncProver for LocalSyncProver<Proof, RootFn, AggFn>
+    where
+        Proof: Serialize + for<'de> Deserialize<'de>,
+        RootFn: Fn(&str) -> anyhow::Result<(Proof, PublicValues<plonky2::field::goldilocks_field::GoldilocksField>)>
+            + Send
+            + Sync,
+        AggFn: Fn(
+                bool,
+                &Proof,
+                bool,
+                &Proof,
+                PublicValues<plonky2::field::goldilocks_field::GoldilocksField>,
+            ) -> anyhow::Result<(Proof, PublicValues<plonky2::field::goldilocks_field::GoldilocksField>)>
+            + Send
+            + Sync,
+    {
+        fn prove_root(&self, seg_path: &str) -> anyhow::Result<Vec<u8>> {
+            let (proof, public_values) = (self.root_fn)(seg_path)?;
+            Ok(serde_json::to_vec(&Envelope {
+                proof,
+                public_values,
+                is_agg: false,
+            })?)
+        }
+
+        fn prove_aggregation(&self, left: &[u8], right: &[u8]) -> anyhow::Result<Vec<u8>> {
+            let left: Envelope<Proof> = serde_json::from_slice(left)?;
+            let right: Envelope<Proof> = serde_json::from_slice(right)?;
+            let public_values = PublicValues {
+                roots_before: left.public_values.roots_before,
+                roots_after: right.public_values.roots_after,
+                userdata: right.public_values.userdata,
+            };
+            let (proof, public_values) = (self.agg_fn)(
+                left.is_agg,
+                &left.proof,
+                right.is_agg,
+                &right.proof,
+                public_values,
+            )?;
+            Ok(serde_json::to_vec(&Envelope {
+                proof,
+                public_values,
+                is_agg: true,
+            })?)
+        }
+    }
+
+    /// Submits work to one of the `SEG_WORKERS` HTTP endpoints (picked by hashing the
+    /// request so repeated calls for the same segment land on the same worker), retrying
+    /// transient failures with a short backoff before giving up.
+    pub struct RemoteSyncProver {
+        pub endpoints: Vec<String>,
+        pub max_retries: usize,
+    }
+
+    impl RemoteSyncProver {
+        fn endpoint_for(&self, key: &[u8]) -> &str {
+            let mut hash: u64 = 0xcbf29ce484222325;
+            for b in key {
+                hash ^= *b as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+            &self.endpoints[(hash as usize) % self.endpoints.len()]
+        }
+
+        fn post_with_retry(&self, route: &str, key: &[u8], body: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+            let endpoint = self.endpoint_for(key);
+            let mut last_err = None;
+            for attempt in 0..=self.max_retries {
+                match ureq::post(&format!("{endpoint}{route}")).send_bytes(&body) {
+                    Ok(resp) => {
+                        let mut buf = Vec::new();
+                        resp.into_reader().read_to_end(&mut buf)?;
+                        return Ok(buf);
+                    }
+                    Err(e) => {
+                        log::warn!("worker {endpoint} attempt {attempt} failed: {e}");
+                        last_err = Some(e);
+                        std::thread::sleep(Duration::from_millis(200 * (attempt as u64 + 1)));
+                    }
+                }
+            }
+            anyhow::bail!(
+                "exhausted {} retries against {:?}: {:?}",
+                self.max_retries,
+                self.endpoints,
+                last_err
+            )
+        }
+    }
+
+    impl SyncProver for RemoteSyncProver {
+        fn prove_root(&self, seg_path: &str) -> anyhow::Result<Vec<u8>> {
+            self.post_with_retry("/prove_root", seg_path.as_bytes(), seg_path.as_bytes().to_vec())
+        }
+
+        fn prove_aggregation(&self, left: &[u8], right: &[u8]) -> anyhow::Result<Vec<u8>> {
+            let mut body = (left.len() as u64).to_le_bytes().to_vec();
+            body.extend_from_slice(left);
+            body.extend_from_slice(right);
+            self.post_with_retry("/prove_aggregation", left, body)
+        }
+    }
+
+    /// Parses `SEG_WORKERS` into a list of worker endpoints; empty means "no remote
+    /// workers configured", which callers should treat as "run in-process".
+    pub fn endpoints_from_env() -> Vec<String> {
+        std::env::var("SEG_WORKERS")
+            .ok()
+            .map(|v| {
+                v.split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
 fn split_elf_into_segs() {
     // 1. split ELF into segs
     let basedir = env::var("BASEDIR").unwrap_or("/tmp/cannon".to_string());
@@ -57,7 +395,12 @@ fn split_elf_into_segs() {
     let new_writer = |_: &str| -> Option<std::fs::File> { None };
     instrumented_state.split_segment(false, &seg_path, new_writer);
     let mut segment_step: usize = seg_size;
-    let new_writer = |name: &str| -> Option<std::fs::File> { File::create(name).ok() };
+    let codec = segment_codec::CompressionType::from_env();
+    let new_writer = move |name: &str| -> Option<segment_codec::CompressingWriter> {
+        File::create(name)
+            .ok()
+            .map(|file| segment_codec::CompressingWriter::new(file, codec))
+    };
     loop {
         if instrumented_state.state.exited {
             break;
@@ -96,7 +439,12 @@ fn prove_sha2_bench() {
     std::fs::create_dir_all(&seg_path).unwrap();
     let new_writer = |_: &str| -> Option<std::fs::File> { None };
     instrumented_state.split_segment(false, &seg_path, new_writer);
-    let new_writer = |name: &str| -> Option<std::fs::File> { File::create(name).ok() };
+    let codec = segment_codec::CompressionType::from_env();
+    let new_writer = move |name: &str| -> Option<segment_codec::CompressingWriter> {
+        File::create(name)
+            .ok()
+            .map(|file| segment_codec::CompressingWriter::new(file, codec))
+    };
     loop {
         if instrumented_state.state.exited {
             break;
@@ -107,7 +455,7 @@ fn prove_sha2_bench() {
     log::info!("Split done {}", instrumented_state.state.step);
 
     let seg_file = format!("{seg_path}/{}", 0);
-    let seg_reader = BufReader::new(File::open(seg_file).unwrap());
+    let seg_reader = BufReader::new(segment_codec::open_segment_reader(&seg_file).unwrap());
     let kernel = segment_kernel(
         "",
         "",
@@ -143,7 +491,7 @@ fn prove_single_seg() {
     let seg_file = env::var("SEG_FILE").expect("Segment file is missing");
     let seg_size = env::var("SEG_SIZE").unwrap_or(format!("{SEGMENT_STEPS}"));
     let seg_size = seg_size.parse::<_>().unwrap_or(SEGMENT_STEPS);
-    let seg_reader = BufReader::new(File::open(seg_file).unwrap());
+    let seg_reader = BufReader::new(segment_codec::open_segment_reader(&seg_file).unwrap());
     let kernel = segment_kernel(&basedir, &block, &file, seg_reader, seg_size);
 
     const D: usize = 2;
@@ -167,8 +515,492 @@ fn prove_single_seg() {
     log::info!("Prove done");
 }
 
+// Runs the split loop and drives per-table trace/witness generation for every produced
+// segment WITHOUT running `prove`/FRI, so callers get a fast pre-flight that catches
+// emulator traps, memory inconsistencies, and table-overflow in seconds.
+fn test_only() -> anyhow::Result<()> {
+    let basedir = env::var("BASEDIR").unwrap_or("/tmp/cannon".to_string());
+    let elf_path = env::var("ELF_PATH").expect("ELF file is missing");
+    let block_no = env::var("BLOCK_NO");
+    let seg_path = env::var("SEG_OUTPUT").expect("Segment output path is missing");
+    let seg_size = env::var("SEG_SIZE").unwrap_or(format!("{SEGMENT_STEPS}"));
+    let seg_size = seg_size.parse::<_>().unwrap_or(SEGMENT_STEPS);
+    let args = env::var("ARGS").unwrap_or("".to_string());
+    let args = args.split_whitespace().collect();
+
+    let data = fs::read(elf_path).expect("could not read file");
+    let file =
+        ElfBytes::<AnyEndian>::minimal_parse(data.as_slice()).expect("opening elf file failed");
+    let (mut state, _) = State::load_elf(&file);
+    state.patch_elf(&file);
+    state.patch_stack(args);
+
+    let block_path = match block_no {
+        Ok(no) => {
+            let block_path = get_block_path(&basedir, &no, "");
+            state.load_input(&block_path);
+            block_path
+        }
+        _ => "".to_string(),
+    };
+
+    let mut instrumented_state = InstrumentedState::new(state, block_path);
+    std::fs::create_dir_all(&seg_path).unwrap();
+    let new_writer = |_: &str| -> Option<std::fs::File> { None };
+    instrumented_state.split_segment(false, &seg_path, new_writer);
+    let mut segment_step: usize = seg_size;
+    let codec = segment_codec::CompressionType::from_env();
+    let new_writer = move |name: &str| -> Option<segment_codec::CompressingWriter> {
+        File::create(name)
+            .ok()
+            .map(|file| segment_codec::CompressingWriter::new(file, codec))
+    };
+    let mut seg_num = 0usize;
+    loop {
+        if instrumented_state.state.exited {
+            break;
+        }
+        instrumented_state.step();
+        segment_step -= 1;
+        if segment_step == 0 {
+            segment_step = seg_size;
+            instrumented_state.split_segment(true, &seg_path, new_writer);
+            seg_num += 1;
+        }
+    }
+    instrumented_state.split_segment(true, &seg_path, new_writer);
+    seg_num += 1;
+    log::info!("Split done {}", instrumented_state.state.step);
+    instrumented_state.dump_memory();
+
+    const D: usize = 2;
+    type C = PoseidonGoldilocksConfig;
+    type F = <C as GenericConfig<D>>::F;
+    let allstark: AllStark<F, D> = AllStark::default();
+    let config = StarkConfig::standard_fast_config();
+
+    let mut all_fit = true;
+    for i in 0..seg_num {
+        let seg_file = format!("{}/{}", seg_path, i);
+        let seg_reader = BufReader::new(segment_codec::open_segment_reader(&seg_file)?);
+        let kernel = segment_kernel("", "", "", seg_reader, seg_size);
+        let mut timing = TimingTree::new("generate traces", log::Level::Info);
+        let (traces, _public_values) = generate_traces(&allstark, &kernel, &config, &mut timing)?;
+        timing.filter(Duration::from_millis(100)).print();
+
+        for (table, trace) in traces.iter().enumerate() {
+            let rows = trace[0].values.len();
+            let degree_bits = log2_ceil(rows);
+            let range = &DEGREE_BITS_RANGE[table];
+            let fits = range.contains(&degree_bits);
+            log::info!(
+                "segment {} table {}: {} rows ({} degree bits), fits {:?}: {}",
+                i,
+                table,
+                rows,
+                degree_bits,
+                range,
+                fits
+            );
+            all_fit &= fits;
+        }
+    }
+
+    if all_fit {
+        log::info!("test_only passed: every table fit its configured degree range");
+        Ok(())
+    } else {
+        anyhow::bail!("test_only failed: a table overflowed its configured degree range")
+    }
+}
+
+// Builds a ready-to-deploy Solidity verifier and `verifyProof` calldata for the wrapped
+// Groth16 block proof that `aggregate_proof_all` saves under `../verifier/data/test_circuit/`.
+//
+// The plonky2 -> Groth16 wrapping itself (common/verifier-only circuit data in, Groth16
+// verifying key + proof out) is done by the gnark-based toolchain that lives in the
+// sibling `../verifier` project; this just drives it and turns its output into the two
+// artifacts a caller actually wants: the `.sol` contract and the calldata to submit it.
 fn prove_groth16() {
-    todo!()
+    let build_path = "../verifier/data".to_string();
+    let data_dir = format!("{}/test_circuit/", build_path);
+
+    log::info!("Generating Groth16 verifying key and proof from {}", data_dir);
+    let status = std::process::Command::new("go")
+        .current_dir("../verifier")
+        .args(["run", ".", "-data", &data_dir])
+        .status()
+        .expect("failed to invoke the ../verifier toolchain (is `go` installed?)");
+    if !status.success() {
+        panic!("groth16 wrapping failed with {status}");
+    }
+
+    // The toolchain drops `verifier.sol` (the contract specialized to this run's VK) and
+    // `proof_with_public_inputs.json` (decimal-string G1/G2 points plus public inputs)
+    // into the same directory.
+    let sol_path = format!("{}verifier.sol", data_dir);
+    let proof_path = format!("{}proof_with_public_inputs.json", data_dir);
+
+    let proof_json: serde_json::Value = serde_json::from_str(
+        &fs::read_to_string(&proof_path).expect("missing proof_with_public_inputs.json"),
+    )
+    .expect("malformed proof_with_public_inputs.json");
+
+    let as_uint256 = |v: &serde_json::Value| -> String {
+        let n: BigUint = v.as_str().unwrap().parse().unwrap();
+        format!("0x{:0>64x}", n)
+    };
+    let pair =
+        |v: &serde_json::Value| -> Vec<String> { v.as_array().unwrap().iter().map(as_uint256).collect() };
+
+    let a = pair(&proof_json["pi_a"]);
+    let b: Vec<Vec<String>> = proof_json["pi_b"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(pair)
+        .collect();
+    let c = pair(&proof_json["pi_c"]);
+    let public_inputs = pair(&proof_json["public_inputs"]);
+
+    let calldata = format!(
+        "verifyProof(\n  [{}],\n  [[{}],[{}]],\n  [{}],\n  [{}]\n)",
+        a.join(","),
+        b[0].join(","),
+        b[1].join(","),
+        c.join(","),
+        public_inputs.join(",")
+    );
+    let calldata_path = format!("{}calldata.txt", data_dir);
+    fs::write(&calldata_path, &calldata).expect("failed to write calldata");
+
+    // `go run` exiting 0 doesn't guarantee the toolchain actually dropped verifier.sol
+    // where we expect it - check it's really there before telling the caller it is.
+    fs::metadata(&sol_path).unwrap_or_else(|e| {
+        panic!("verifier toolchain reported success but {sol_path} is missing: {e}")
+    });
+
+    log::info!("Solidity verifier: {}", sol_path);
+    log::info!("verifyProof calldata: {}", calldata_path);
+}
+
+// SnarkPack-style logarithmic aggregation of many independent wrapped Groth16 block
+// proofs: a GIPA recursion folds the (A, B, C) vectors and the SRS in half each round,
+// producing O(log M) cross-commitments instead of re-running recursive plonky2
+// aggregation over every block.
+mod snarkpack {
+    use ark_bn254::{Bn254, Fr, G1Affine, G1Projective, G2Affine, G2Projective};
+    use ark_ec::pairing::{Pairing, PairingOutput};
+    use ark_ec::CurveGroup;
+    use ark_ff::{Field, One, UniformRand};
+    use ark_serialize::CanonicalSerialize;
+    use ark_std::rand::{rngs::StdRng, SeedableRng};
+    use sha2::{Digest, Sha256};
+
+    /// A single wrapped Groth16 block proof plus the public inputs it was proven against.
+    pub struct WrappedProof {
+        pub a: G1Affine,
+        pub b: G2Affine,
+        pub c: G1Affine,
+        pub public_inputs: Vec<Fr>,
+    }
+
+    /// Structured reference string: consecutive powers of two toxic-waste scalars, one
+    /// side in G1 and the other in G2, so each half can be folded independently per round.
+    pub struct Srs {
+        pub g1_powers: Vec<G1Affine>,
+        pub g2_powers: Vec<G2Affine>,
+    }
+
+    impl Srs {
+        /// Generates an SRS of the given length from a seed. Real deployments would run
+        /// this as a trusted setup/powers-of-tau ceremony instead of sampling in-process.
+        pub fn structured(len: usize, seed: u64) -> Self {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let a = Fr::rand(&mut rng);
+            let b = Fr::rand(&mut rng);
+            let mut g1_powers = Vec::with_capacity(len);
+            let mut g2_powers = Vec::with_capacity(len);
+            let mut ga = G1Projective::from(G1Affine::identity());
+            let mut hb = G2Projective::from(G2Affine::identity());
+            let g1_gen = G1Projective::from(G1Affine::generator());
+            let g2_gen = G2Projective::from(G2Affine::generator());
+            let mut a_pow = Fr::one();
+            let mut b_pow = Fr::one();
+            for _ in 0..len {
+                ga = g1_gen * a_pow;
+                hb = g2_gen * b_pow;
+                g1_powers.push(ga.into_affine());
+                g2_powers.push(hb.into_affine());
+                a_pow *= a;
+                b_pow *= b;
+            }
+            Srs { g1_powers, g2_powers }
+        }
+    }
+
+    fn commit(g1: &[G1Affine], g2: &[G2Affine], c1: &[G1Affine]) -> (PairingOutput<Bn254>, PairingOutput<Bn254>) {
+        let com_ab = Bn254::multi_pairing(g1.iter().copied(), g2.iter().copied());
+        let com_c = Bn254::multi_pairing(c1.iter().copied(), g2.iter().copied());
+        (com_ab, com_c)
+    }
+
+    fn fold_g1(lo: &[G1Affine], hi: &[G1Affine], x: Fr) -> Vec<G1Affine> {
+        lo.iter()
+            .zip(hi.iter())
+            .map(|(l, h)| (G1Projective::from(*l) + G1Projective::from(*h) * x).into_affine())
+            .collect()
+    }
+
+    fn fold_g2(lo: &[G2Affine], hi: &[G2Affine], x_inv: Fr) -> Vec<G2Affine> {
+        lo.iter()
+            .zip(hi.iter())
+            .map(|(l, h)| (G2Projective::from(*l) + G2Projective::from(*h) * x_inv).into_affine())
+            .collect()
+    }
+
+    fn challenge(
+        round: usize,
+        left: &[PairingOutput<Bn254>],
+        right: &[PairingOutput<Bn254>],
+        pi_left_hash: &[u8; 32],
+        pi_right_hash: &[u8; 32],
+    ) -> Fr {
+        let mut hasher = Sha256::new();
+        hasher.update(round.to_le_bytes());
+        for p in left.iter().chain(right.iter()) {
+            let mut bytes = Vec::new();
+            p.serialize_compressed(&mut bytes).unwrap();
+            hasher.update(&bytes);
+        }
+        hasher.update(pi_left_hash);
+        hasher.update(pi_right_hash);
+        Fr::from_le_bytes_mod_order(&hasher.finalize())
+    }
+
+    /// Digests one half of the folded public-input vectors so it can be mixed into the
+    /// Fiat-Shamir transcript without blowing up the proof with the raw field elements.
+    fn hash_pi(half: &[Vec<Fr>]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        for inputs in half {
+            for x in inputs {
+                let mut bytes = Vec::new();
+                x.serialize_compressed(&mut bytes).unwrap();
+                hasher.update(&bytes);
+            }
+        }
+        hasher.finalize().into()
+    }
+
+    fn fold_pi(lo: &[Vec<Fr>], hi: &[Vec<Fr>], x: Fr) -> Vec<Vec<Fr>> {
+        lo.iter()
+            .zip(hi.iter())
+            .map(|(l, h)| l.iter().zip(h.iter()).map(|(a, b)| *a + *b * x).collect())
+            .collect()
+    }
+
+    /// One GIPA round's cross-commitments: `left` pairs the low half of one vector against
+    /// the high half of the SRS/other vector, `right` is the mirror image. The public-input
+    /// hashes are folded into the round's challenge so the transcript - and therefore the
+    /// proof - is bound to the public inputs the aggregated proofs were made against.
+    #[derive(PartialEq)]
+    pub struct Round {
+        pub left: (PairingOutput<Bn254>, PairingOutput<Bn254>),
+        pub right: (PairingOutput<Bn254>, PairingOutput<Bn254>),
+        pub pi_left_hash: [u8; 32],
+        pub pi_right_hash: [u8; 32],
+    }
+
+    #[derive(PartialEq)]
+    pub struct AggregatedProof {
+        pub rounds: Vec<Round>,
+        pub final_a: G1Affine,
+        pub final_b: G2Affine,
+        pub final_c: G1Affine,
+        pub final_srs: (G1Affine, G2Affine),
+        pub final_public_inputs: Vec<Fr>,
+    }
+
+    /// Recursively halves the (A, B, C) vectors, the SRS and the public inputs, folding
+    /// each in half with a Fiat-Shamir challenge per round, until a single proof triple
+    /// remains.
+    pub fn aggregate(srs: &Srs, proofs: &[WrappedProof]) -> AggregatedProof {
+        assert!(proofs.len().is_power_of_two(), "GIPA requires a power-of-two proof count");
+
+        let mut a: Vec<G1Affine> = proofs.iter().map(|p| p.a).collect();
+        let mut b: Vec<G2Affine> = proofs.iter().map(|p| p.b).collect();
+        let mut c: Vec<G1Affine> = proofs.iter().map(|p| p.c).collect();
+        let mut pi: Vec<Vec<Fr>> = proofs.iter().map(|p| p.public_inputs.clone()).collect();
+        let mut g1 = srs.g1_powers.clone();
+        let mut g2 = srs.g2_powers.clone();
+
+        let mut rounds = Vec::new();
+
+        let mut round = 0;
+        while a.len() > 1 {
+            let mid = a.len() / 2;
+            let (a_lo, a_hi) = a.split_at(mid);
+            let (b_lo, b_hi) = b.split_at(mid);
+            let (c_lo, c_hi) = c.split_at(mid);
+            let (g1_lo, g1_hi) = g1.split_at(mid);
+            let (g2_lo, g2_hi) = g2.split_at(mid);
+            let (pi_lo, pi_hi) = pi.split_at(mid);
+
+            let left = commit(a_lo, g2_hi, c_lo);
+            let right = commit(a_hi, g2_lo, c_hi);
+            let _ = (b_lo, b_hi); // B folds alongside A/C but isn't separately committed here.
+
+            let pi_left_hash = hash_pi(pi_lo);
+            let pi_right_hash = hash_pi(pi_hi);
+
+            let x = challenge(round, &[left.0, left.1], &[right.0, right.1], &pi_left_hash, &pi_right_hash);
+            let x_inv = x.inverse().expect("challenge is never zero with overwhelming probability");
+
+            a = fold_g1(a_lo, a_hi, x);
+            c = fold_g1(c_lo, c_hi, x);
+            b = fold_g2(b_lo, b_hi, x_inv);
+            g1 = fold_g1(g1_lo, g1_hi, x_inv);
+            // G2 must fold with the reciprocal exponent used for A/C: that's what makes
+            // com(A, G2) and com(C, G2) telescope round to round (com' = com + x^-1*left +
+            // x*right) instead of picking up stray cross terms that never cancel. `verify`
+            // does not check this relation; it re-runs the whole fold.
+            g2 = fold_g2(g2_lo, g2_hi, x_inv);
+            pi = fold_pi(pi_lo, pi_hi, x);
+
+            rounds.push(Round { left, right, pi_left_hash, pi_right_hash });
+            round += 1;
+        }
+
+        AggregatedProof {
+            rounds,
+            final_a: a[0],
+            final_b: b[0],
+            final_c: c[0],
+            final_srs: (g1[0], g2[0]),
+            final_public_inputs: pi.into_iter().next().unwrap(),
+        }
+    }
+
+    /// Checks an `AggregatedProof` against the public SRS and the original per-proof
+    /// A/B/C/public-input data it's supposed to vouch for. A previous version of this
+    /// function took only the `AggregatedProof` and trusted a commitment value the proof
+    /// carried about itself - a dishonest aggregator could skip the real GIPA recursion,
+    /// pick arbitrary final values and round terms, and solve for a matching "anchor"
+    /// after the fact, so that check passed for a completely fabricated proof. Re-deriving
+    /// the fold here from `srs`/`proofs` (public data neither party controls after the
+    /// fact) and comparing the result against what was submitted closes that gap, and as
+    /// a consequence binds B and the public inputs exactly the same way A/C are bound,
+    /// rather than leaving them along for the ride.
+    ///
+    /// This replays the whole O(log n)-round fold rather than using the
+    /// multi-scalar-multiplication trick a production verifier would use to check it in
+    /// O(log n) pairings; that optimization doesn't change what's being demonstrated here.
+    pub fn verify(srs: &Srs, proofs: &[WrappedProof], proof: &AggregatedProof) -> bool {
+        aggregate(srs, proofs) == *proof
+    }
+}
+
+fn parse_g1(v: &serde_json::Value) -> ark_bn254::G1Affine {
+    let arr = v.as_array().unwrap();
+    let x: ark_bn254::Fq = arr[0].as_str().unwrap().parse().unwrap();
+    let y: ark_bn254::Fq = arr[1].as_str().unwrap().parse().unwrap();
+    ark_bn254::G1Affine::new(x, y)
+}
+
+fn parse_g2(v: &serde_json::Value) -> ark_bn254::G2Affine {
+    let arr = v.as_array().unwrap();
+    let x: ark_bn254::Fq2 = ark_bn254::Fq2::new(
+        arr[0][0].as_str().unwrap().parse().unwrap(),
+        arr[0][1].as_str().unwrap().parse().unwrap(),
+    );
+    let y: ark_bn254::Fq2 = ark_bn254::Fq2::new(
+        arr[1][0].as_str().unwrap().parse().unwrap(),
+        arr[1][1].as_str().unwrap().parse().unwrap(),
+    );
+    ark_bn254::G2Affine::new(x, y)
+}
+
+// SnarkPack-style aggregation over every wrapped Groth16 proof saved under `proof_dir`
+// (one subdirectory per block, each holding a `proof_with_public_inputs.json` in the same
+// layout `prove_groth16` reads). Pads to the next power of two by repeating the last
+// proof, since GIPA's halving recursion needs one. Writes only a one-line summary (proof
+// and round counts) to `AGG_OUTPUT`; the aggregated proof itself is not saved.
+fn aggregate_snarkpack() -> anyhow::Result<()> {
+    let proof_dir = env::var("AGG_PROOF_DIR").expect("directory of saved wrapped proofs is missing");
+    let out_path = env::var("AGG_OUTPUT").unwrap_or(format!("{}/snarkpack_summary.txt", proof_dir));
+
+    let mut entries: Vec<_> = fs::read_dir(&proof_dir)?
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_dir())
+        .collect();
+    entries.sort_by_key(|e| e.file_name());
+
+    let mut proofs: Vec<snarkpack::WrappedProof> = Vec::with_capacity(entries.len());
+    for entry in &entries {
+        let proof_path = entry.path().join("proof_with_public_inputs.json");
+        let proof_json: serde_json::Value = serde_json::from_str(&fs::read_to_string(&proof_path)?)?;
+        proofs.push(snarkpack::WrappedProof {
+            a: parse_g1(&proof_json["pi_a"]),
+            b: parse_g2(&proof_json["pi_b"]),
+            c: parse_g1(&proof_json["pi_c"]),
+            public_inputs: proof_json["public_inputs"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|v| v.as_str().unwrap().parse().unwrap())
+                .collect(),
+        });
+    }
+    anyhow::ensure!(!proofs.is_empty(), "no wrapped proofs found under {}", proof_dir);
+
+    let padded_len = proofs.len().next_power_of_two();
+    if padded_len != proofs.len() {
+        log::info!(
+            "padding {} proofs up to {} by repeating the last one",
+            proofs.len(),
+            padded_len
+        );
+        while proofs.len() < padded_len {
+            let last = proofs.last().unwrap();
+            proofs.push(snarkpack::WrappedProof {
+                a: last.a,
+                b: last.b,
+                c: last.c,
+                public_inputs: last.public_inputs.clone(),
+            });
+        }
+    }
+
+    // A literal, hardcoded seed would make the SRS's toxic-waste scalars public and
+    // guessable by anyone, defeating the point of a structured reference string - this
+    // has to come from wherever the real trusted setup/powers-of-tau artifact is pinned.
+    let srs_seed: u64 = env::var("SNARKPACK_SRS_SEED")
+        .expect("SNARKPACK_SRS_SEED is missing (it must come from a real trusted setup, never a hardcoded constant)")
+        .parse()
+        .expect("SNARKPACK_SRS_SEED must be a u64");
+    let srs = snarkpack::Srs::structured(padded_len, srs_seed);
+    let timing = TimingTree::new("snarkpack aggregate", log::Level::Info);
+    let aggregated = snarkpack::aggregate(&srs, &proofs);
+    timing.filter(Duration::from_millis(100)).print();
+
+    anyhow::ensure!(
+        snarkpack::verify(&srs, &proofs, &aggregated),
+        "snarkpack aggregation failed to verify"
+    );
+
+    log::info!(
+        "aggregated {} proofs into {} rounds, bound to {} public inputs (O(log M) instead of {} recursive aggregations)",
+        proofs.len(),
+        aggregated.rounds.len(),
+        aggregated.final_public_inputs.len(),
+        proofs.len() - 1
+    );
+    fs::write(
+        &out_path,
+        format!("aggregated {} proofs into {} rounds", proofs.len(), aggregated.rounds.len()),
+    )?;
+    log::info!("snarkpack proof summary written to {}", out_path);
+    Ok(())
 }
 
 fn main() {
@@ -176,7 +1008,7 @@ fn main() {
     let args: Vec<String> = env::args().collect();
     let helper = || {
         log::info!(
-            "Help: {} split | prove | aggregate_proof | aggregate_proof_all | prove_groth16 | bench",
+            "Help: {} split | prove | aggregate_proof | aggregate_proof_all | test_only | prove_groth16 | aggregate_snarkpack | bench",
             args[0]
         );
         std::process::exit(-1);
@@ -189,7 +1021,14 @@ fn main() {
         "prove" => prove_single_seg(),
         "aggregate_proof" => aggregate_proof().unwrap(),
         "aggregate_proof_all" => aggregate_proof_all().unwrap(),
+        "test_only" => {
+            if let Err(e) = test_only() {
+                log::error!("{e}");
+                std::process::exit(1);
+            }
+        }
         "prove_groth16" => prove_groth16(),
+        "aggregate_snarkpack" => aggregate_snarkpack().unwrap(),
         "bench" => prove_sha2_bench(),
         _ => helper(),
     };
@@ -213,7 +1052,7 @@ fn aggregate_proof() -> anyhow::Result<()> {
     let all_circuits =
         AllRecursiveCircuits::<F, C, D>::new(&all_stark, &DEGREE_BITS_RANGE, &config);
 
-    let seg_reader = BufReader::new(File::open(seg_file)?);
+    let seg_reader = BufReader::new(segment_codec::open_segment_reader(&seg_file)?);
     let input_first = segment_kernel(&basedir, &block, &file, seg_reader, seg_size);
     let mut timing = TimingTree::new("prove root first", log::Level::Info);
     let (root_proof_first, first_public_values) =
@@ -222,7 +1061,7 @@ fn aggregate_proof() -> anyhow::Result<()> {
     timing.filter(Duration::from_millis(100)).print();
     all_circuits.verify_root(root_proof_first.clone())?;
 
-    let seg_reader = BufReader::new(File::open(seg_file2)?);
+    let seg_reader = BufReader::new(segment_codec::open_segment_reader(&seg_file2)?);
     let input = segment_kernel(&basedir, &block, &file, seg_reader, seg_size);
     let mut timing = TimingTree::new("prove root second", log::Level::Info);
     let (root_proof, public_values) =
@@ -290,116 +1129,101 @@ fn aggregate_proof_all() -> anyhow::Result<()> {
     let all_circuits =
         AllRecursiveCircuits::<F, C, D>::new(&all_stark, &DEGREE_BITS_RANGE, &config);
 
-    let seg_file = format!("{}/{}", seg_dir, 0);
-    log::info!("Process segment 0");
-    let seg_reader = BufReader::new(File::open(seg_file)?);
-    let input_first = segment_kernel(&basedir, &block, &file, seg_reader, seg_size);
-    let mut timing = TimingTree::new("prove root first", log::Level::Info);
-    let (mut agg_proof, mut updated_agg_public_values) =
-        all_circuits.prove_root(&all_stark, &input_first, &config, &mut timing)?;
+    // Dispatch root proving and aggregation through a `SyncProver`: in-process by
+    // default, or fanned out to `SEG_WORKERS` when set.
+    let workers = proving_client::endpoints_from_env();
 
-    timing.filter(Duration::from_millis(100)).print();
-    all_circuits.verify_root(agg_proof.clone())?;
-
-    let mut base_seg = 1;
-    let mut is_agg = false;
-
-    if seg_file_number % 2 == 0 {
-        let seg_file = format!("{}/{}", seg_dir, 1);
-        log::info!("Process segment 1");
-        let seg_reader = BufReader::new(File::open(seg_file)?);
-        let input = segment_kernel(&basedir, &block, &file, seg_reader, seg_size);
-        timing = TimingTree::new("prove root second", log::Level::Info);
-        let (root_proof, public_values) =
-            all_circuits.prove_root(&all_stark, &input, &config, &mut timing)?;
-        timing.filter(Duration::from_millis(100)).print();
+    // Prove every segment root independently, then fold the roots together two at a
+    // time in a balanced binary tree so the critical path is O(log N) instead of O(N).
+    // Capping this at the local core count makes sense in-process, but it also caps how
+    // many segments can be in flight across the cluster once `SEG_WORKERS` is set - size
+    // off the worker count there instead so the fan-out actually uses the cluster.
+    let seg_parallelism = env::var("SEG_PARALLELISM")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or_else(|| {
+            if workers.is_empty() {
+                std::thread::available_parallelism().map_or(1, |n| n.get())
+            } else {
+                workers.len()
+            }
+        });
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(seg_parallelism)
+        .build()?;
+    let prover: Box<dyn proving_client::SyncProver + '_> = if workers.is_empty() {
+        let all_stark_ref = &all_stark;
+        let all_circuits_ref = &all_circuits;
+        let config_ref = &config;
+        let basedir = basedir.clone();
+        let block = block.clone();
+        let file = file.clone();
+        Box::new(proving_client::LocalSyncProver::new(
+            move |seg_path: &str| {
+                let seg_reader = BufReader::new(segment_codec::open_segment_reader(seg_path)?);
+                let input = segment_kernel(&basedir, &block, &file, seg_reader, seg_size);
+                let mut timing = TimingTree::new("prove root", log::Level::Info);
+                let (root_proof, public_values) =
+                    all_circuits_ref.prove_root(all_stark_ref, &input, config_ref, &mut timing)?;
+                timing.filter(Duration::from_millis(100)).print();
+                all_circuits_ref.verify_root(root_proof.clone())?;
+                anyhow::Ok((root_proof, public_values))
+            },
+            move |left_is_agg, left, right_is_agg, right, public_values| {
+                let timing = TimingTree::new("prove aggregation", log::Level::Info);
+                let (agg_proof, updated_agg_public_values) = all_circuits_ref.prove_aggregation(
+                    left_is_agg,
+                    left,
+                    right_is_agg,
+                    right,
+                    public_values,
+                )?;
+                timing.filter(Duration::from_millis(100)).print();
+                all_circuits_ref.verify_aggregation(&agg_proof)?;
+                anyhow::Ok((agg_proof, updated_agg_public_values))
+            },
+        ))
+    } else {
+        log::info!("fanning segment proving out to workers: {:?}", workers);
+        Box::new(proving_client::RemoteSyncProver {
+            endpoints: workers,
+            max_retries: 3,
+        })
+    };
 
-        all_circuits.verify_root(root_proof.clone())?;
-
-        // Update public values for the aggregation.
-        let agg_public_values = PublicValues {
-            roots_before: updated_agg_public_values.roots_before,
-            roots_after: public_values.roots_after,
-            userdata: public_values.userdata,
-        };
-        timing = TimingTree::new("prove aggression", log::Level::Info);
-        // We can duplicate the proofs here because the state hasn't mutated.
-        (agg_proof, updated_agg_public_values) = all_circuits.prove_aggregation(
-            false,
-            &agg_proof,
-            false,
-            &root_proof,
-            agg_public_values.clone(),
-        )?;
-        timing.filter(Duration::from_millis(100)).print();
-        all_circuits.verify_aggregation(&agg_proof)?;
+    let mut level: Vec<Vec<u8>> = pool.install(|| {
+        (0..seg_file_number)
+            .into_par_iter()
+            .map(|i| -> anyhow::Result<_> {
+                log::info!("Process segment {}", i);
+                prover.prove_root(&format!("{}/{}", seg_dir, i))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+    })?;
 
-        is_agg = true;
-        base_seg = 2;
+    while level.len() > 1 {
+        level = pool.install(|| {
+            level
+                .into_par_iter()
+                .chunks(2)
+                .map(|mut pair| -> anyhow::Result<_> {
+                    if pair.len() == 1 {
+                        return Ok(pair.pop().unwrap());
+                    }
+                    let right = pair.pop().unwrap();
+                    let left = pair.pop().unwrap();
+                    prover.prove_aggregation(&left, &right)
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+        })?;
     }
 
-    for i in 0..(seg_file_number - base_seg) / 2 {
-        let seg_file = format!("{}/{}", seg_dir, base_seg + (i << 1));
-        log::info!("Process segment {}", base_seg + (i << 1));
-        let seg_reader = BufReader::new(File::open(&seg_file)?);
-        let input_first = segment_kernel(&basedir, &block, &file, seg_reader, seg_size);
-        let mut timing = TimingTree::new("prove root first", log::Level::Info);
-        let (root_proof_first, first_public_values) =
-            all_circuits.prove_root(&all_stark, &input_first, &config, &mut timing)?;
-
-        timing.filter(Duration::from_millis(100)).print();
-        all_circuits.verify_root(root_proof_first.clone())?;
-
-        let seg_file = format!("{}/{}", seg_dir, base_seg + (i << 1) + 1);
-        log::info!("Process segment {}", base_seg + (i << 1) + 1);
-        let seg_reader = BufReader::new(File::open(&seg_file)?);
-        let input = segment_kernel(&basedir, &block, &file, seg_reader, seg_size);
-        let mut timing = TimingTree::new("prove root second", log::Level::Info);
-        let (root_proof, public_values) =
-            all_circuits.prove_root(&all_stark, &input, &config, &mut timing)?;
-        timing.filter(Duration::from_millis(100)).print();
-
-        all_circuits.verify_root(root_proof.clone())?;
-
-        // Update public values for the aggregation.
-        let new_agg_public_values = PublicValues {
-            roots_before: first_public_values.roots_before,
-            roots_after: public_values.roots_after,
-            userdata: public_values.userdata,
-        };
-        timing = TimingTree::new("prove aggression", log::Level::Info);
-        // We can duplicate the proofs here because the state hasn't mutated.
-        let (new_agg_proof, new_updated_agg_public_values) = all_circuits.prove_aggregation(
-            false,
-            &root_proof_first,
-            false,
-            &root_proof,
-            new_agg_public_values,
-        )?;
-        timing.filter(Duration::from_millis(100)).print();
-        all_circuits.verify_aggregation(&new_agg_proof)?;
-
-        // Update public values for the nested aggregation.
-        let agg_public_values = PublicValues {
-            roots_before: updated_agg_public_values.roots_before,
-            roots_after: new_updated_agg_public_values.roots_after,
-            userdata: new_updated_agg_public_values.userdata,
-        };
-        timing = TimingTree::new("prove nested aggression", log::Level::Info);
-
-        // We can duplicate the proofs here because the state hasn't mutated.
-        (agg_proof, updated_agg_public_values) = all_circuits.prove_aggregation(
-            is_agg,
-            &agg_proof,
-            true,
-            &new_agg_proof,
-            agg_public_values.clone(),
-        )?;
-        is_agg = true;
-        timing.filter(Duration::from_millis(100)).print();
-
-        all_circuits.verify_aggregation(&agg_proof)?;
-    }
+    let proving_client::Envelope {
+        proof: agg_proof,
+        public_values: updated_agg_public_values,
+        is_agg: _,
+    } = serde_json::from_slice(&level.into_iter().next().expect("at least one segment"))?;
 
     let (block_proof, _block_public_values) =
         all_circuits.prove_block(None, &agg_proof, updated_agg_public_values)?;